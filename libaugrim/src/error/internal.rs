@@ -14,6 +14,7 @@
 
 //! Module containing InternalError implementation.
 
+use std::backtrace::{Backtrace, BacktraceStatus};
 use std::error;
 use std::fmt;
 
@@ -22,6 +23,41 @@ struct Source {
     source: Box<dyn error::Error>,
 }
 
+/// The call site that produced an `InternalError`, captured by the `internal_error!` macro.
+///
+/// This is distinct from the error's backtrace: it records a single, precise location (the
+/// macro invocation site) rather than a full call stack, and is always cheap to capture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+    file: &'static str,
+    module: &'static str,
+    line: u32,
+}
+
+impl SourceLocation {
+    /// Constructs a new `SourceLocation`. This is intended to be called from the
+    /// `internal_error!` macro using `file!()`, `module_path!()`, and `line!()`.
+    pub fn new(file: &'static str, module: &'static str, line: u32) -> Self {
+        Self { file, module, line }
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Indicates whether an `InternalError` is worth retrying.
+///
+/// Most internal errors represent logic errors and are `Permanent`, but some, such as a timed-out
+/// peer I/O operation, are `Transient` and may succeed if the operation is retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transience {
+    Permanent,
+    Transient,
+}
+
 /// An error which is returned for reasons internal to the function.
 ///
 /// This error is produced when a failure occurred within the function but the failure is due to an
@@ -31,6 +67,9 @@ struct Source {
 pub struct InternalError {
     message: Option<String>,
     source: Option<Source>,
+    backtrace: Option<Backtrace>,
+    location: Option<SourceLocation>,
+    kind: Transience,
 }
 
 impl InternalError {
@@ -55,6 +94,9 @@ impl InternalError {
                 prefix: None,
                 source,
             }),
+            backtrace: Some(Backtrace::capture()),
+            location: None,
+            kind: Transience::Permanent,
         }
     }
 
@@ -79,6 +121,9 @@ impl InternalError {
                 prefix: None,
                 source,
             }),
+            backtrace: Some(Backtrace::capture()),
+            location: None,
+            kind: Transience::Permanent,
         }
     }
 
@@ -104,6 +149,9 @@ impl InternalError {
                 prefix: Some(prefix),
                 source,
             }),
+            backtrace: Some(Backtrace::capture()),
+            location: None,
+            kind: Transience::Permanent,
         }
     }
 
@@ -124,6 +172,9 @@ impl InternalError {
         Self {
             message: Some(message),
             source: None,
+            backtrace: Some(Backtrace::capture()),
+            location: None,
+            kind: Transience::Permanent,
         }
     }
 
@@ -138,14 +189,83 @@ impl InternalError {
 
         self.to_string()
     }
+
+    /// Returns the backtrace captured when this error was constructed, if one was captured.
+    ///
+    /// A backtrace is only captured when the `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`
+    /// environment variable is enabled; otherwise this will return `None`.
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        self.backtrace
+            .as_ref()
+            .filter(|backtrace| backtrace.status() == BacktraceStatus::Captured)
+    }
+
+    /// Attaches a `SourceLocation` to this error.
+    ///
+    /// This is used by the `internal_error!` macro to record the call site of the macro
+    /// invocation and is not intended to be called directly.
+    pub(crate) fn with_location(mut self, location: SourceLocation) -> Self {
+        self.location = Some(location);
+        self
+    }
+
+    /// Returns an `ErrorReport` which renders this error and its full causal chain.
+    pub fn report(&self) -> ErrorReport<'_> {
+        ErrorReport::new(self)
+    }
+
+    /// Constructs a new `InternalError` from a specified source error, marked as `Transient`.
+    ///
+    /// This is equivalent to `InternalError::from_source`, except that `is_transient` will
+    /// return `true`, indicating that the failure may succeed if the operation is retried.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use augrim::error::InternalError;
+    ///
+    /// let io_err = std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out");
+    /// let internal_error = InternalError::from_transient_source(Box::new(io_err));
+    /// assert!(internal_error.is_transient());
+    /// ```
+    pub fn from_transient_source(source: Box<dyn error::Error>) -> Self {
+        Self::from_source(source).transient()
+    }
+
+    /// Marks this error as `Transient`, indicating that retrying the operation which produced it
+    /// may succeed.
+    pub fn transient(mut self) -> Self {
+        self.kind = Transience::Transient;
+        self
+    }
+
+    /// Returns `true` if this error is marked as `Transient`, indicating that the operation
+    /// which produced it may be worth retrying.
+    pub fn is_transient(&self) -> bool {
+        self.kind == Transience::Transient
+    }
+
+    /// Returns a reference to the source error, if one was provided, as a trait object.
+    ///
+    /// Unlike [`source`](error::Error::source), this is available directly on `InternalError`
+    /// without going through the `Error` trait, and the returned reference can be downcast with
+    /// `downcast_ref` to recover the concrete source type.
+    pub fn source_ref(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.source.as_ref().map(|s| s.source.as_ref())
+    }
+
+    /// Consumes the error, returning the boxed source error, if one was provided.
+    ///
+    /// This drops the message/prefix wrapper, returning the original source by value so it can
+    /// be downcast with `downcast` to recover the concrete source type.
+    pub fn into_source(self) -> Option<Box<dyn error::Error>> {
+        self.source.map(|s| s.source)
+    }
 }
 
 impl error::Error for InternalError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        match &self.source {
-            Some(s) => Some(s.source.as_ref()),
-            None => None,
-        }
+        self.source_ref()
     }
 }
 
@@ -180,14 +300,148 @@ impl fmt::Debug for InternalError {
             debug_struct.field("source", &source.source);
         }
 
-        debug_struct.finish()
+        if let Some(backtrace) = &self.backtrace {
+            if backtrace.status() == BacktraceStatus::Captured {
+                debug_struct.field("backtrace", backtrace);
+            }
+        }
+
+        if self.kind == Transience::Transient {
+            debug_struct.field("kind", &self.kind);
+        }
+
+        debug_struct.finish()?;
+
+        if let Some(location) = &self.location {
+            write!(f, " at {}", location)?;
+        }
+
+        Ok(())
     }
 }
 
+/// A wrapper which renders an error and its full `source()` chain.
+///
+/// `Display` for most errors only shows the outermost layer, leaving a nested source to hide its
+/// root cause. `ErrorReport` walks the chain, printing the head error followed by an indented
+/// "Caused by:" list of each successive source.
+///
+/// # Examples
+///
+/// ```
+/// use augrim::error::InternalError;
+///
+/// let root = InternalError::with_message("disk full".to_string());
+/// let err = InternalError::from_source_with_prefix(Box::new(root), "could not save state".to_string());
+/// println!("{}", err.report());
+/// ```
+pub struct ErrorReport<'a> {
+    error: &'a (dyn error::Error + 'static),
+    show_backtrace: bool,
+}
+
+impl<'a> ErrorReport<'a> {
+    /// Constructs a new `ErrorReport` wrapping the given error.
+    pub fn new(error: &'a (dyn error::Error + 'static)) -> Self {
+        Self {
+            error,
+            show_backtrace: false,
+        }
+    }
+
+    /// Sets whether the backtrace of the wrapped error, if any, should be appended after the
+    /// causal chain.
+    ///
+    /// Only a wrapped `InternalError` with a captured backtrace has one appended; for any other
+    /// error type, no backtrace is shown.
+    pub fn with_backtrace(mut self, show_backtrace: bool) -> Self {
+        self.show_backtrace = show_backtrace;
+        self
+    }
+}
+
+impl<'a> fmt::Display for ErrorReport<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)?;
+
+        let mut source = self.error.source();
+        if source.is_some() {
+            write!(f, "\n\nCaused by:")?;
+        }
+
+        let mut i = 0;
+        while let Some(err) = source {
+            write!(f, "\n{:>4}: {}", i, err)?;
+            source = err.source();
+            i += 1;
+        }
+
+        if let Some(backtrace) = self
+            .show_backtrace
+            .then(|| self.error.downcast_ref::<InternalError>())
+            .flatten()
+            .and_then(InternalError::backtrace)
+        {
+            write!(f, "\n\n{}", backtrace)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Constructs an `InternalError`, recording the file, module, and line of the invocation site
+/// as a `SourceLocation` on the resulting error.
+///
+/// Accepts the same forms as the `InternalError` constructors:
+///
+/// ```ignore
+/// internal_error!("oops");
+/// internal_error!("failed at round {}", round);
+/// internal_error!(source => "could not open file");
+/// ```
+macro_rules! internal_error {
+    ($source:expr => $prefix:expr) => {
+        $crate::error::InternalError::from_source_with_prefix(
+            Box::new($source),
+            $prefix.to_string(),
+        )
+        .with_location($crate::error::SourceLocation::new(
+            file!(),
+            module_path!(),
+            line!(),
+        ))
+    };
+    ($msg:expr) => {
+        $crate::error::InternalError::with_message($msg.to_string()).with_location(
+            $crate::error::SourceLocation::new(file!(), module_path!(), line!()),
+        )
+    };
+    ($fmt:expr, $($arg:tt)*) => {
+        $crate::error::InternalError::with_message(format!($fmt, $($arg)*)).with_location(
+            $crate::error::SourceLocation::new(file!(), module_path!(), line!()),
+        )
+    };
+}
+
+pub(crate) use internal_error;
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
 
+    /// Clears the captured backtrace on `err` and, if present, on its source, so that `Debug`
+    /// assertions are deterministic regardless of whether `RUST_BACKTRACE`/`RUST_LIB_BACKTRACE`
+    /// is set in the environment the tests run under.
+    fn clear_backtraces(mut err: InternalError) -> InternalError {
+        err.backtrace = None;
+        if let Some(source) = &mut err.source {
+            if let Some(inner) = source.source.downcast_mut::<InternalError>() {
+                inner.backtrace = None;
+            }
+        }
+        err
+    }
+
     /// Tests that errors constructed with `InternalError::from_source` return a debug string of
     /// the form `format!("InternalError { {:?} }", source)`.
     #[test]
@@ -196,7 +450,7 @@ pub mod tests {
         let debug = "InternalError { source: InternalError { message: \"test message\" } }";
         let err =
             InternalError::from_source(Box::new(InternalError::with_message(msg.to_string())));
-        assert_eq!(format!("{:?}", err), debug);
+        assert_eq!(format!("{:?}", clear_backtraces(err)), debug);
     }
 
     /// Tests that errors constructed with `InternalError::from_source_with_message` return a debug
@@ -210,7 +464,7 @@ pub mod tests {
             Box::new(InternalError::with_message("unused".to_string())),
             msg.to_string(),
         );
-        assert_eq!(format!("{:?}", err), debug);
+        assert_eq!(format!("{:?}", clear_backtraces(err)), debug);
     }
 
     /// Tests that errors constructed with `InternalError::from_source_with_prefix` return a debug
@@ -225,7 +479,7 @@ pub mod tests {
             Box::new(InternalError::with_message(msg.to_string())),
             prefix.to_string(),
         );
-        assert_eq!(format!("{:?}", err), debug);
+        assert_eq!(format!("{:?}", clear_backtraces(err)), debug);
     }
 
     /// Tests that errors constructed with `InternalError::with_message` return a debug
@@ -235,7 +489,7 @@ pub mod tests {
         let msg = "test message";
         let debug = "InternalError { message: \"test message\" }";
         let err = InternalError::with_message(msg.to_string());
-        assert_eq!(format!("{:?}", err), debug);
+        assert_eq!(format!("{:?}", clear_backtraces(err)), debug);
     }
 
     /// Tests that error constructed with `InternalError::from_source` return a display
@@ -281,4 +535,78 @@ pub mod tests {
         let err = InternalError::with_message(msg.to_string());
         assert_eq!(format!("{}", err), msg);
     }
+
+    /// Tests that `internal_error!` attaches a `SourceLocation` pointing at the invocation site,
+    /// without changing the `Display` output.
+    #[test]
+    fn test_internal_error_macro_captures_location() {
+        let line = line!() + 1;
+        let err = internal_error!("test message");
+        assert_eq!(format!("{}", err), "test message");
+        assert_eq!(err.location, Some(SourceLocation::new(file!(), module_path!(), line)));
+    }
+
+    /// Tests that `ErrorReport` prints the head error's display followed by a numbered,
+    /// indented "Caused by:" list of each successive source.
+    #[test]
+    fn test_error_report_renders_full_chain() {
+        let root = InternalError::with_message("disk full".to_string());
+        let mid = InternalError::from_source_with_prefix(
+            Box::new(root),
+            "could not write checkpoint".to_string(),
+        );
+        let top = InternalError::from_source_with_message(Box::new(mid), "round failed".to_string());
+
+        let report = format!("{}", top.report());
+        assert_eq!(
+            report,
+            "round failed\n\nCaused by:\n   0: could not write checkpoint: disk full\n   1: disk full"
+        );
+    }
+
+    /// Tests that `source_ref` returns the concrete source error, which can be downcast back to
+    /// its original type.
+    #[test]
+    fn test_source_ref_downcasts_to_concrete_type() {
+        let io_err = std::io::Error::new(std::io::ErrorKind::Other, "io error");
+        let err = InternalError::from_source(Box::new(io_err));
+
+        let source = err.source_ref().expect("source should be present");
+        assert!(source.downcast_ref::<std::io::Error>().is_some());
+    }
+
+    /// Tests that `into_source` returns the boxed source by value, dropping the message/prefix
+    /// wrapper.
+    #[test]
+    fn test_into_source_returns_boxed_source() {
+        let io_err = std::io::Error::new(std::io::ErrorKind::Other, "io error");
+        let err = InternalError::from_source_with_prefix(
+            Box::new(io_err),
+            "could not open file".to_string(),
+        );
+
+        let source = err.into_source().expect("source should be present");
+        assert!(source.downcast::<std::io::Error>().is_ok());
+    }
+
+    /// Tests that errors default to `Permanent` and that `transient` marks them as `Transient`
+    /// without changing the `Display` output.
+    #[test]
+    fn test_transience_defaults_to_permanent() {
+        let msg = "test message";
+        let err = InternalError::with_message(msg.to_string());
+        assert!(!err.is_transient());
+
+        let err = err.transient();
+        assert!(err.is_transient());
+        assert_eq!(format!("{}", err), msg);
+    }
+
+    /// Tests that `from_transient_source` constructs an error marked as `Transient`.
+    #[test]
+    fn test_from_transient_source_is_transient() {
+        let io_err = std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out");
+        let err = InternalError::from_transient_source(Box::new(io_err));
+        assert!(err.is_transient());
+    }
 }